@@ -1,5 +1,7 @@
 extern crate bincode;
 extern crate rustc_serialize;
+#[cfg(unix)]
+extern crate libc;
 
 use std::io::Error as IoError;
 use bincode::{EncodingError, DecodingError};
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Uniquely identifies a message among all messages a single `Sender` has
+/// sent to one destination address. Scoped per destination rather than
+/// globally, so fanning a message out to several peers (see
+/// `enqueue_broadcast`) still leaves each of them its own contiguous run of
+/// ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub struct MsgId(pub u64);
+
+/// The position of a chunk within its message: `PieceNum(index, total)`,
+/// both one-indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct PieceNum(pub u16, pub u16);
+
+/// A single chunk of a larger message, as it travels over the wire.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct MsgChunk(pub MsgId, pub PieceNum, pub Vec<u8>);
+
+/// A fully reassembled message, with the bytes of every chunk concatenated
+/// back together in order.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct CompleteMessage(pub MsgId, pub Vec<u8>);
+
+/// The contents of a single UDP datagram: one or more chunks, possibly from
+/// different messages, coalesced together by the sender to make better use
+/// of the packet.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Datagram(pub Vec<MsgChunk>);
+
+struct PartialMessage {
+    total: u16,
+    pieces: HashMap<u16, Vec<u8>>,
+    last_chunk_at: Instant,
+}
+
+impl PartialMessage {
+    fn new(total: u16) -> PartialMessage {
+        PartialMessage {
+            total: total,
+            pieces: HashMap::new(),
+            last_chunk_at: Instant::now(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces.len() == self.total as usize
+    }
+
+    fn into_complete(self, id: MsgId) -> CompleteMessage {
+        let mut buf = Vec::new();
+        for i in 1 .. self.total + 1 {
+            let piece = self.pieces.get(&i).expect("message was complete");
+            buf.extend(piece.iter().cloned());
+        }
+        CompleteMessage(id, buf)
+    }
+}
+
+/// Tracks the in-flight, partially-received messages for a single peer.
+pub struct MsgQueue {
+    partials: HashMap<MsgId, PartialMessage>,
+    max_size: Option<usize>,
+}
+
+impl MsgQueue {
+    pub fn new(max_size: Option<usize>) -> MsgQueue {
+        MsgQueue {
+            partials: HashMap::new(),
+            max_size: max_size,
+        }
+    }
+
+    /// Feeds a chunk into the queue. Returns `Some(message)` once every
+    /// chunk of that message has arrived, dropping its partial state.
+    pub fn insert_chunk(&mut self, chunk: MsgChunk) -> Option<CompleteMessage> {
+        let MsgChunk(id, PieceNum(index, total), data) = chunk;
+
+        if !self.partials.contains_key(&id) {
+            if let Some(max) = self.max_size {
+                if self.partials.len() >= max {
+                    return None;
+                }
+            }
+            self.partials.insert(id, PartialMessage::new(total));
+        }
+
+        {
+            let partial = self.partials.get_mut(&id).unwrap();
+            partial.pieces.insert(index, data);
+            partial.last_chunk_at = Instant::now();
+        }
+
+        if self.partials.get(&id).unwrap().is_complete() {
+            let partial = self.partials.remove(&id).unwrap();
+            Some(partial.into_complete(id))
+        } else {
+            None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partials.is_empty()
+    }
+
+    /// Drops any partial message whose most recent chunk is older than
+    /// `max_age`, returning the number of messages dropped.
+    pub fn housekeep(&mut self, max_age: Duration) -> usize {
+        let before = self.partials.len();
+        self.partials.retain(|_, partial| partial.last_chunk_at.elapsed() < max_age);
+        before - self.partials.len()
+    }
+}
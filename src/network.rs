@@ -1,20 +1,80 @@
-use std::collections::{VecDeque, HashMap, HashSet};
+use std::collections::{VecDeque, HashMap, HashSet, BTreeMap};
 use std::net::{UdpSocket, ToSocketAddrs, SocketAddr};
 use std::io::Result as IoResult;
+use std::io::ErrorKind as IoErrorKind;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
 
 use super::msgqueue::*;
-use super::UnrResult;
+use super::{UnrResult, UnrError};
 use bincode;
 
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
 static MSG_PADDING: u16 = 32;
 
 /// The sending end of an unreliable message socket.
 pub struct Sender {
     out_queue: VecDeque<(MsgChunk, AddrsContainer)>,
-    last_id: u64,
+    /// The last `MsgId` handed out to each destination, kept separately per
+    /// address so that `enqueue_broadcast`-style fan-out to several peers
+    /// doesn't interleave one shared counter across all of them -- each
+    /// destination sees its own contiguous run of ids, which is what
+    /// `poll_ordered` assumes.
+    last_id: HashMap<SocketAddr, u64>,
     socket: UdpSocket,
+    buf_pool: BufferPool,
     pub datagram_length: u16,
-    pub replication: u8
+    pub replication: u8,
+    /// When set, `enqueue_broadcast` fans a message out to every address
+    /// currently known to this table, typically shared with a `Receiver`
+    /// that learns peers as it receives from them.
+    pub peers: Option<PeerTable>
+}
+
+/// A small free-list of reusable datagram buffers, so `Receiver::poll` and
+/// `Sender::send_one` reuse their datagram buffer instead of allocating one
+/// per packet once the pool has been warmed up. The batch and packed paths
+/// do not draw from the pool.
+struct BufferPool {
+    free: VecDeque<Vec<u8>>,
+    buf_len: usize
+}
+
+impl BufferPool {
+    fn new(buf_len: usize) -> BufferPool {
+        BufferPool { free: VecDeque::new(), buf_len: buf_len }
+    }
+
+    fn with_capacity(buf_len: usize, count: usize) -> BufferPool {
+        let free = (0 .. count).map(|_| Vec::with_capacity(buf_len)).collect();
+        BufferPool { free: free, buf_len: buf_len }
+    }
+
+    /// Hands out a cleared buffer, reusing one from the free-list if one
+    /// is available.
+    fn take(&mut self) -> Vec<u8> {
+        match self.free.pop_front() {
+            Some(mut buf) => {
+                buf.clear();
+                buf
+            }
+            None => Vec::with_capacity(self.buf_len)
+        }
+    }
+
+    /// Returns a buffer to the free-list for a future `take` to reuse.
+    fn recycle(&mut self, mut buf: Vec<u8>) {
+        buf.clear();
+        self.free.push_back(buf);
+    }
 }
 
 pub enum ReceiverFilter {
@@ -22,13 +82,112 @@ pub enum ReceiverFilter {
     Blacklist(HashSet<SocketAddr>)
 }
 
+/// A table of addresses a `Receiver` has heard from, shareable with a
+/// `Sender` so it can reply to or broadcast toward them without the caller
+/// tracking addresses by hand.
+///
+/// Cloning a `PeerTable` is cheap and shares the same underlying table, so
+/// the same instance can be handed to both halves of a socket pair.
+#[derive(Clone)]
+pub struct PeerTable {
+    peers: Arc<Mutex<HashMap<SocketAddr, Instant>>>
+}
+
+impl PeerTable {
+    /// Creates an empty, shareable peer table.
+    pub fn new() -> PeerTable {
+        PeerTable { peers: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Records (or refreshes) `addr` as having just been heard from.
+    pub fn learn(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().insert(addr, Instant::now());
+    }
+
+    /// Returns whether `addr` is currently a known peer.
+    pub fn lookup(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(addr)
+    }
+
+    /// Every address currently known to the table, in no particular order.
+    pub fn known_peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Forgets a peer entirely.
+    pub fn remove_all(&self, addr: &SocketAddr) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    /// Drops any peer not learned (or re-learned) in more than `max_age`.
+    pub fn housekeep(&self, max_age: Duration) {
+        self.peers.lock().unwrap().retain(|_, last_seen| last_seen.elapsed() < max_age);
+    }
+}
+
+/// An event produced by `poll_ordered`: either the next message in
+/// sequence from a peer, or notice that a gap in its sequence could not be
+/// filled and was skipped over.
+#[derive(Debug)]
+pub enum OrderedEvent {
+    Message(CompleteMessage),
+    Skipped(MsgId)
+}
+
+/// Per-peer bookkeeping for `poll_ordered`'s in-order delivery window.
+struct OrderWindow {
+    next_id: u64,
+    buffered: BTreeMap<u64, CompleteMessage>,
+    gap_since: Option<Instant>,
+    /// Last time this window was touched by `feed_ordered`, used by
+    /// `Receiver::housekeep` to evict peers that stopped sending entirely.
+    last_active: Instant
+}
+
+impl OrderWindow {
+    fn new() -> OrderWindow {
+        OrderWindow {
+            next_id: 1,
+            buffered: BTreeMap::new(),
+            gap_since: None,
+            last_active: Instant::now()
+        }
+    }
+}
+
 /// The receiving end of an unreliable message socket.
 pub struct Receiver {
     socket: UdpSocket,
     queue: HashMap<SocketAddr, MsgQueue>,
+    /// Messages that finished reassembling because a later chunk in the
+    /// same (packed) datagram completed them, but haven't been handed to
+    /// the caller yet.
+    pending: VecDeque<(SocketAddr, CompleteMessage)>,
+    buf_pool: BufferPool,
+    order_windows: HashMap<SocketAddr, OrderWindow>,
+    /// Extra `OrderedEvent`s produced by a single `feed_ordered`/
+    /// `try_release_ordered` call -- a skipped gap spanning several ids
+    /// yields several `Skipped` events, but `poll_ordered` can only return
+    /// one at a time, so the rest wait here.
+    ordered_pending: VecDeque<(SocketAddr, OrderedEvent)>,
     pub datagram_length: u16,
     pub max_connection_size: Option<usize>,
-    pub filter: ReceiverFilter
+    pub filter: ReceiverFilter,
+    /// When set, `poll` opportunistically evicts partial messages older
+    /// than this, the same way `housekeep` does explicitly.
+    pub message_timeout: Option<Duration>,
+    /// How long `poll_ordered` will wait for a missing `MsgId` before
+    /// giving up on it and resuming from the next message it already has
+    /// buffered, emitting an `OrderedEvent::Skipped` for each id it drops.
+    pub ordered_gap_timeout: Option<Duration>,
+    /// Caps how many out-of-order messages `poll_ordered` will buffer per
+    /// peer before forcing the same kind of skip `ordered_gap_timeout`
+    /// would, regardless of how long the gap has been open.
+    pub ordered_max_buffered: Option<usize>,
+    /// When set, every address a datagram is accepted from is learned
+    /// into this table, typically shared with a `Sender` for replies or
+    /// `enqueue_broadcast`.
+    pub peers: Option<PeerTable>
 }
 
 #[derive(Debug, Clone)]
@@ -76,38 +235,375 @@ impl Receiver {
             socket: socket,
             datagram_length: datagram_length,
             queue: HashMap::new(),
+            pending: VecDeque::new(),
+            buf_pool: BufferPool::new(datagram_length as usize),
+            order_windows: HashMap::new(),
+            ordered_pending: VecDeque::new(),
             max_connection_size: max_connection_size,
             filter: filter,
+            message_timeout: None,
+            ordered_gap_timeout: None,
+            ordered_max_buffered: None,
+            peers: None,
         }
     }
 
+    /// Like `from_socket`, but pre-warms the receive buffer pool with
+    /// `pool_size` buffers up front instead of growing it lazily.
+    pub fn with_capacity(socket: UdpSocket, datagram_length: u16, max_connection_size: Option<usize>, filter: ReceiverFilter, pool_size: usize) -> Receiver {
+        let mut receiver = Receiver::from_socket(socket, datagram_length, max_connection_size, filter);
+        receiver.buf_pool = BufferPool::with_capacity(datagram_length as usize, pool_size);
+        receiver
+    }
+
     /// Blocks until a completed message is received, and returns the Socket
     /// Address that the message came from.
+    ///
+    /// A single datagram may carry several coalesced chunks (see the
+    /// sender's packing mode); if more than one message completes while
+    /// decoding it, the extras are buffered and handed out by later calls
+    /// before the socket is read again.
     pub fn poll(&mut self) -> UnrResult<(SocketAddr, CompleteMessage)> {
-        let mut buf: Vec<u8> = (0 .. self.datagram_length).map(|_| 0).collect();
+        if let Some(ready) = self.pending.pop_front() {
+            return Ok(ready);
+        }
+
+        let mut buf = self.buf_pool.take();
+        let result = self.poll_with_buf(&mut buf);
+        self.buf_pool.recycle(buf);
+        result
+    }
+
+    fn poll_with_buf(&mut self, buf: &mut Vec<u8>) -> UnrResult<(SocketAddr, CompleteMessage)> {
         loop {
+            if let Some(max_age) = self.message_timeout {
+                self.housekeep(max_age);
+            }
+
+            buf.resize(self.datagram_length as usize, 0);
             let (amnt, from) = try!(self.socket.recv_from(&mut buf[..]));
             // Filter the incoming connection through the whitelist or blacklist.
             if !self.filter.allow_through(&from) {
                 continue;
             }
+            if let Some(ref peers) = self.peers {
+                peers.learn(from);
+            }
 
             let data = &buf[0 .. amnt];
-            let chunk: MsgChunk = try!(bincode::decode(data));
+            let Datagram(chunks) = try!(bincode::decode(data));
 
             let max_size = self.max_connection_size.clone();
-            let q = self.queue.entry(from.clone())
-                              .or_insert_with(|| MsgQueue::new(max_size));
-            if let Some(completed) = q.insert_chunk(chunk) {
+            let mut first = None;
+            for chunk in chunks {
+                let q = self.queue.entry(from.clone())
+                                  .or_insert_with(|| MsgQueue::new(max_size));
+                if let Some(completed) = q.insert_chunk(chunk) {
+                    if first.is_none() {
+                        first = Some(completed);
+                    } else {
+                        self.pending.push_back((from, completed));
+                    }
+                }
+            }
+
+            if let Some(completed) = first {
                 return Ok((from, completed));
             }
         }
     }
 
+    /// Like `poll`, but releases messages per sender in contiguous `MsgId`
+    /// order rather than arrival order, buffering ones that arrive early
+    /// until the gap fills in.
+    ///
+    /// If a gap is never filled, it sits in the buffer until either
+    /// `ordered_gap_timeout` elapses or `ordered_max_buffered` is
+    /// exceeded, at which point the missing id is skipped and delivery
+    /// resumes from the lowest buffered id; callers learn about the skip
+    /// through `OrderedEvent::Skipped`. Gaps are only checked opportunely,
+    /// each time a new message arrives from any peer (the same pattern
+    /// `message_timeout` uses for `poll`) -- with neither limit set, a
+    /// single missing message blocks everything after it from that peer
+    /// forever.
+    pub fn poll_ordered(&mut self) -> UnrResult<(SocketAddr, OrderedEvent)> {
+        if let Some(ready) = self.ordered_pending.pop_front() {
+            return Ok(ready);
+        }
+
+        loop {
+            if let Some(ready) = self.try_release_ordered() {
+                return Ok(ready);
+            }
+
+            let (from, msg) = try!(self.poll());
+            if let Some(event) = self.feed_ordered(from, msg) {
+                return Ok((from, event));
+            }
+        }
+    }
+
+    /// Feeds a just-completed message into its peer's order window,
+    /// returning an event if it (or the overflow it causes) is ready to
+    /// be released immediately. When a gap skip abandons more than one id,
+    /// the first `Skipped` event is returned and the rest are queued onto
+    /// `ordered_pending` for subsequent `poll_ordered` calls.
+    fn feed_ordered(&mut self, from: SocketAddr, msg: CompleteMessage) -> Option<OrderedEvent> {
+        let max_buffered = self.ordered_max_buffered;
+        let overflow = {
+            let window = self.order_windows.entry(from).or_insert_with(OrderWindow::new);
+            window.last_active = Instant::now();
+            let MsgId(id) = msg.0;
+
+            if id < window.next_id {
+                // A stale duplicate of something we already delivered or skipped past.
+                return None;
+            }
+
+            if id == window.next_id {
+                window.next_id += 1;
+                // Later ids may still be buffered behind a fresh gap; keep
+                // the timer running for it until nothing is left waiting.
+                window.gap_since = if window.buffered.is_empty() {
+                    None
+                } else {
+                    Some(Instant::now())
+                };
+                return Some(OrderedEvent::Message(msg));
+            }
+
+            if window.buffered.is_empty() {
+                window.gap_since = Some(Instant::now());
+            }
+            window.buffered.insert(id, msg);
+
+            match max_buffered {
+                Some(max) if window.buffered.len() > max => Some(skip_gap(window)),
+                _ => None
+            }
+        };
+
+        self.queue_overflow(from, overflow)
+    }
+
+    /// Scans every peer's order window for a message that can be released
+    /// immediately: either the next contiguous id already sitting in the
+    /// buffer, or a gap that has been open longer than `ordered_gap_timeout`.
+    fn try_release_ordered(&mut self) -> Option<(SocketAddr, OrderedEvent)> {
+        let gap_timeout = self.ordered_gap_timeout;
+        let mut ready = None;
+        for (&addr, window) in self.order_windows.iter_mut() {
+            if let Some(msg) = window.buffered.remove(&window.next_id) {
+                window.next_id += 1;
+                // The removal may have exposed a fresh gap (e.g. 2,3,5
+                // buffered, 1 arrives, 2 and 3 drain here leaving 5 stuck
+                // behind missing 4) -- only clear the timer once nothing
+                // is left waiting behind it.
+                window.gap_since = if window.buffered.is_empty() {
+                    None
+                } else {
+                    Some(Instant::now())
+                };
+                ready = Some((addr, vec![OrderedEvent::Message(msg)]));
+                break;
+            }
+
+            let timed_out = match (gap_timeout, window.gap_since) {
+                (Some(timeout), Some(since)) => since.elapsed() >= timeout,
+                _ => false
+            };
+            if timed_out {
+                ready = Some((addr, skip_gap(window)));
+                break;
+            }
+        }
+
+        match ready {
+            Some((addr, events)) => self.queue_overflow(addr, Some(events)).map(|e| (addr, e)),
+            None => None
+        }
+    }
+
+    /// Returns the first event of `events` (if any), pushing the rest onto
+    /// `ordered_pending` tagged with `from` for later `poll_ordered` calls.
+    fn queue_overflow(&mut self, from: SocketAddr, events: Option<Vec<OrderedEvent>>) -> Option<OrderedEvent> {
+        let mut events = match events {
+            Some(events) => events,
+            None => return None
+        };
+        let first = events.remove(0);
+        for event in events {
+            self.ordered_pending.push_back((from, event));
+        }
+        Some(first)
+    }
+
+    /// Drops any partial message, from any peer, whose most recent chunk
+    /// is older than `max_age`. Peers left with no partial messages are
+    /// removed entirely.
+    ///
+    /// Also evicts any `poll_ordered` order window that hasn't been fed a
+    /// message in that long, so a peer that stops sending (or whose
+    /// traffic moved to another destination) doesn't leave its bookkeeping
+    /// allocated forever. Before dropping a window, every id from its
+    /// `next_id` through the highest one it has buffered is drained onto
+    /// `ordered_pending` -- a `Message` for ids it actually completed, a
+    /// `Skipped` for ids it was still waiting on -- so a caller using
+    /// `poll_ordered` still learns about every message the window was
+    /// holding instead of it silently vanishing.
+    pub fn housekeep(&mut self, max_age: Duration) {
+        for queue in self.queue.values_mut() {
+            queue.housekeep(max_age);
+        }
+        self.queue.retain(|_, queue| !queue.is_empty());
+
+        let stale: Vec<SocketAddr> = self.order_windows.iter()
+            .filter(|&(_, window)| window.last_active.elapsed() >= max_age)
+            .map(|(&addr, _)| addr)
+            .collect();
+        for addr in stale {
+            let window = self.order_windows.remove(&addr).unwrap();
+            for event in drain_window(window) {
+                self.ordered_pending.push_back((addr, event));
+            }
+        }
+    }
+
     /// Removes all stored incomplete messages from a specific address.
     pub fn clear_addr(&mut self, addr: &SocketAddr) {
         self.queue.remove(addr);
     }
+
+    /// Reads up to `max` datagrams in as few syscalls as possible (via
+    /// `recvmmsg(2)` on platforms that have it), decoding each one into a
+    /// `Datagram` and feeding its chunks through the sending peer's
+    /// `MsgQueue`.
+    ///
+    /// Unlike `poll`, this never blocks waiting for a datagram to arrive:
+    /// it returns as soon as the kernel has no more to hand back, which may
+    /// be an empty `Vec` if nothing was waiting.
+    pub fn poll_batch(&mut self, max: usize) -> UnrResult<Vec<(SocketAddr, CompleteMessage)>> {
+        let mut completed: Vec<(SocketAddr, CompleteMessage)> = self.pending.drain(..).collect();
+        let datagrams = try!(self.recv_batch(max));
+
+        for (from, buf) in datagrams {
+            if !self.filter.allow_through(&from) {
+                continue;
+            }
+            if let Some(ref peers) = self.peers {
+                peers.learn(from);
+            }
+
+            let Datagram(chunks) = try!(bincode::decode(&buf[..]));
+            let max_size = self.max_connection_size.clone();
+            for chunk in chunks {
+                let q = self.queue.entry(from.clone())
+                                  .or_insert_with(|| MsgQueue::new(max_size));
+                if let Some(msg) = q.insert_chunk(chunk) {
+                    completed.push((from, msg));
+                }
+            }
+        }
+
+        Ok(completed)
+    }
+
+    /// Drains up to `max` waiting datagrams into `(from, bytes)` pairs
+    /// without blocking, using `recvmmsg(2)` to do it in a single syscall.
+    #[cfg(unix)]
+    fn recv_batch(&mut self, max: usize) -> UnrResult<Vec<(SocketAddr, Vec<u8>)>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+
+        let datagram_length = self.datagram_length as usize;
+        let mut bufs: Vec<Vec<u8>> = (0 .. max).map(|_| vec![0u8; datagram_length]).collect();
+        let mut addrs: Vec<libc::sockaddr_storage> = (0 .. max).map(|_| unsafe { mem::zeroed() }).collect();
+        let mut iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|b| {
+            libc::iovec { iov_base: b.as_mut_ptr() as *mut _, iov_len: b.len() }
+        }).collect();
+        let mut headers: Vec<libc::mmsghdr> = (0 .. max).map(|i| unsafe {
+            let mut hdr: libc::msghdr = mem::zeroed();
+            hdr.msg_name = &mut addrs[i] as *mut _ as *mut _;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            hdr.msg_iov = &mut iovecs[i] as *mut _;
+            hdr.msg_iovlen = 1;
+            libc::mmsghdr { msg_hdr: hdr, msg_len: 0 }
+        }).collect();
+
+        try!(self.socket.set_nonblocking(true));
+        let received = unsafe {
+            libc::recvmmsg(
+                self.socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                max as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ::std::ptr::null_mut())
+        };
+        let io_err = if received < 0 { Some(::std::io::Error::last_os_error()) } else { None };
+        try!(self.socket.set_nonblocking(false));
+
+        if let Some(e) = io_err {
+            if e.kind() == IoErrorKind::WouldBlock {
+                return Ok(Vec::new());
+            }
+            return Err(e.into());
+        }
+
+        let mut out = Vec::with_capacity(received as usize);
+        for i in 0 .. received as usize {
+            let from = sockaddr_storage_to_socket_addr(&addrs[i]);
+            let len = headers[i].msg_len as usize;
+            out.push((from, bufs[i][0 .. len].to_vec()));
+        }
+        Ok(out)
+    }
+
+    /// Portable fallback for platforms without `recvmmsg(2)`: repeatedly
+    /// calls `recv_from` on a non-blocking socket until it would block.
+    #[cfg(not(unix))]
+    fn recv_batch(&mut self, max: usize) -> UnrResult<Vec<(SocketAddr, Vec<u8>)>> {
+        let mut out = Vec::new();
+        if max == 0 {
+            return Ok(out);
+        }
+
+        let mut buf: Vec<u8> = (0 .. self.datagram_length).map(|_| 0).collect();
+        try!(self.socket.set_nonblocking(true));
+
+        loop {
+            if out.len() >= max {
+                break;
+            }
+            match self.socket.recv_from(&mut buf[..]) {
+                Ok((amnt, from)) => out.push((from, buf[0 .. amnt].to_vec())),
+                Err(ref e) if e.kind() == IoErrorKind::WouldBlock => break,
+                Err(e) => {
+                    try!(self.socket.set_nonblocking(false));
+                    return Err(e.into());
+                }
+            }
+        }
+
+        try!(self.socket.set_nonblocking(false));
+        Ok(out)
+    }
+}
+
+#[cfg(unix)]
+fn sockaddr_storage_to_socket_addr(storage: &libc::sockaddr_storage) -> SocketAddr {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr: libc::sockaddr_in = unsafe { mem::transmute_copy(storage) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            SocketAddr::new(IpAddr::V4(ip), u16::from_be(addr.sin_port))
+        }
+        _ => {
+            let addr: libc::sockaddr_in6 = unsafe { mem::transmute_copy(storage) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            SocketAddr::new(IpAddr::V6(ip), u16::from_be(addr.sin6_port))
+        }
+    }
 }
 
 impl Sender {
@@ -120,18 +616,37 @@ impl Sender {
     pub fn from_socket(socket: UdpSocket, datagram_length: u16, replication: u8) -> Sender {
         Sender {
             out_queue: VecDeque::new(),
-            last_id: 0,
+            last_id: HashMap::new(),
             socket: socket,
+            buf_pool: BufferPool::new(datagram_length as usize),
             datagram_length: datagram_length,
-            replication: replication
+            replication: replication,
+            peers: None
         }
     }
 
+    /// Like `from_socket`, but pre-warms the encode buffer pool with
+    /// `pool_size` buffers up front instead of growing it lazily.
+    pub fn with_capacity(socket: UdpSocket, datagram_length: u16, replication: u8, pool_size: usize) -> Sender {
+        let mut sender = Sender::from_socket(socket, datagram_length, replication);
+        sender.buf_pool = BufferPool::with_capacity(datagram_length as usize, pool_size);
+        sender
+    }
+
     /// Adds a message to the queue of chunks to send out.
+    ///
+    /// `MsgId`s are handed out per destination address, not globally, so
+    /// that a `Sender` talking to several destinations (see
+    /// `enqueue_broadcast`) gives each of them its own contiguous sequence
+    /// rather than interleaving one shared counter across all of them.
     pub fn enqueue<T: ToSocketAddrs>(&mut self, message: Vec<u8>, addrs: T) -> UnrResult<()> {
-        self.last_id += 1;
-        let id = self.last_id;
         let addrs = try!(AddrsContainer::from_to_sock(addrs));
+        let dest = try!(resolve_addr(&addrs));
+        let id = {
+            let next = self.last_id.entry(dest).or_insert(0);
+            *next += 1;
+            *next
+        };
         let num_chunks = message.len() / ((self.datagram_length - MSG_PADDING) as usize);
 
         for _ in 0 .. self.replication {
@@ -149,6 +664,23 @@ impl Sender {
         Ok(())
     }
 
+    /// Enqueues `message` to every address currently known to `self.peers`,
+    /// for request/response and group-messaging patterns without the
+    /// caller tracking addresses by hand. A no-op if no peer table is set
+    /// or none are known yet.
+    pub fn enqueue_broadcast(&mut self, message: Vec<u8>) -> UnrResult<()> {
+        let known = match self.peers {
+            Some(ref table) => table.known_peers(),
+            None => Vec::new()
+        };
+
+        for addr in known {
+            try!(self.enqueue(message.clone(), addr));
+        }
+
+        Ok(())
+    }
+
     /// Attempts to send one UDP packet over the network.
     ///
     /// The size of the UDP packet is bounded by `self.datagram_length`.
@@ -158,10 +690,14 @@ impl Sender {
     /// * Ok(true) if there are more messages in the queue.
     /// * Ok(false) if theere are no more messages in the queue.
     pub fn send_one(&mut self) -> UnrResult<bool> {
-        let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
         if let Some((next, addrs)) = self.out_queue.pop_front() {
-            let bytes = try!(bincode::encode(&next, bound));
-            try!(self.socket.send_to(&bytes[..], addrs));
+            let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
+            let mut buf = self.buf_pool.take();
+            let result = bincode::encode_into(&Datagram(vec![next]), &mut buf, bound)
+                .map_err(UnrError::from)
+                .and_then(|_| self.socket.send_to(&buf[..], addrs).map_err(UnrError::from));
+            self.buf_pool.recycle(buf);
+            try!(result);
         }
 
         Ok(!self.out_queue.is_empty())
@@ -180,4 +716,288 @@ impl Sender {
     pub fn queue_len(&self) -> usize {
         self.out_queue.len()
     }
+
+    /// Sends one UDP packet, greedily coalescing as many further queued
+    /// chunks bound for the same destination as will fit, instead of
+    /// sending one datagram per chunk.
+    ///
+    /// This trades a little bit of lookahead bookkeeping for much better
+    /// use of the datagram on chatty workloads with many small messages.
+    ///
+    /// ## Returns
+    /// * Err(e) if an error occurred during sending.
+    /// * Ok(true) if there are more messages in the queue.
+    /// * Ok(false) if there are no more messages in the queue.
+    pub fn send_packed(&mut self) -> UnrResult<bool> {
+        let (first_chunk, first_addrs) = match self.out_queue.pop_front() {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        let dest = match resolve_addr(&first_addrs) {
+            Ok(dest) => dest,
+            Err(e) => {
+                self.requeue_front(vec![(first_chunk, first_addrs)]);
+                return Err(e);
+            }
+        };
+
+        let mut taken = vec![(first_chunk.clone(), first_addrs)];
+        let mut chunks = vec![first_chunk];
+        let mut leftover = VecDeque::with_capacity(self.out_queue.len());
+        while let Some((chunk, addrs)) = self.out_queue.pop_front() {
+            let this_dest = match resolve_addr(&addrs) {
+                Ok(dest) => dest,
+                Err(e) => {
+                    taken.push((chunk, addrs));
+                    taken.extend(leftover);
+                    self.requeue_front(taken);
+                    return Err(e);
+                }
+            };
+            if this_dest != dest {
+                leftover.push_back((chunk, addrs));
+                continue;
+            }
+
+            let mut candidate = chunks.clone();
+            candidate.push(chunk.clone());
+            let candidate_len = match datagram_encoded_len(&Datagram(candidate)) {
+                Ok(len) => len,
+                Err(e) => {
+                    taken.push((chunk, addrs));
+                    taken.extend(leftover);
+                    self.requeue_front(taken);
+                    return Err(e);
+                }
+            };
+            if candidate_len <= self.datagram_length as usize {
+                taken.push((chunk.clone(), addrs.clone()));
+                chunks.push(chunk);
+            } else {
+                leftover.push_back((chunk, addrs));
+            }
+        }
+        self.out_queue = leftover;
+
+        let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
+        let bytes = match bincode::encode(&Datagram(chunks), bound) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                self.requeue_front(taken);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = self.socket.send_to(&bytes[..], dest) {
+            self.requeue_front(taken);
+            return Err(e.into());
+        }
+
+        Ok(!self.out_queue.is_empty())
+    }
+
+    /// Pushes `items` back onto the front of `out_queue`, in their original
+    /// order, so a partially-built `send_packed` attempt that fails partway
+    /// through doesn't lose whatever it had already taken off the queue.
+    fn requeue_front(&mut self, items: Vec<(MsgChunk, AddrsContainer)>) {
+        for item in items.into_iter().rev() {
+            self.out_queue.push_front(item);
+        }
+    }
+
+    /// Attempts to send all UDP packets by repeatedly calling `send_packed`.
+    pub fn send_all_packed(&mut self) -> UnrResult<()> {
+        while try!(self.send_packed()) {}
+        Ok(())
+    }
+
+    /// Drains the entire out-queue in as few syscalls as possible (via
+    /// `sendmmsg(2)` where available), encoding each queued chunk and
+    /// sending it to its destination.
+    ///
+    /// Entries are only removed from `out_queue` once `send_batch_encoded`
+    /// confirms they were actually handed to the kernel; anything it
+    /// didn't get to (an early `sendmmsg` short-count, or a hard error
+    /// partway through) is left queued for the next call, the same
+    /// guarantee `send_one`/`send_packed` give for the single message
+    /// they're sending when they fail.
+    ///
+    /// ## Returns
+    /// * Err(e) if an error occurred while sending.
+    /// * Ok(true) if there are more messages in the queue.
+    /// * Ok(false) if there are no more messages in the queue.
+    pub fn send_batch(&mut self) -> UnrResult<bool> {
+        let bound = bincode::SizeLimit::Bounded(self.datagram_length as u64);
+        let mut encoded = Vec::with_capacity(self.out_queue.len());
+        for &(ref chunk, ref addrs) in self.out_queue.iter() {
+            let bytes = try!(bincode::encode(&Datagram(vec![chunk.clone()]), bound));
+            let addr = try!(resolve_addr(addrs));
+            encoded.push((bytes, addr));
+        }
+
+        let sent = try!(self.send_batch_encoded(&encoded));
+        for _ in 0 .. sent {
+            self.out_queue.pop_front();
+        }
+
+        Ok(!self.out_queue.is_empty())
+    }
+
+    /// Sends as many of `encoded` as possible via `sendmmsg(2)`, returning
+    /// the number confirmed sent (from the front) so the caller knows how
+    /// much of its queue to drop. A hard error is only returned once
+    /// nothing at all has been confirmed sent yet; if some were already
+    /// handed to the kernel before the error, it's swallowed in favor of
+    /// reporting that partial progress, and the rest stay queued for the
+    /// next call.
+    #[cfg(unix)]
+    fn send_batch_encoded(&mut self, encoded: &[(Vec<u8>, SocketAddr)]) -> UnrResult<usize> {
+        if encoded.is_empty() {
+            return Ok(0);
+        }
+
+        let mut storages: Vec<libc::sockaddr_storage> = encoded.iter()
+            .map(|&(_, addr)| socket_addr_to_sockaddr_storage(addr))
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = encoded.iter().map(|&(ref bytes, _)| {
+            libc::iovec {
+                iov_base: bytes.as_ptr() as *mut _,
+                iov_len: bytes.len(),
+            }
+        }).collect();
+
+        let mut headers: Vec<libc::mmsghdr> = (0 .. encoded.len()).map(|i| unsafe {
+            let (namelen, _) = sockaddr_len_and_family(&encoded[i].1);
+            let mut hdr: libc::msghdr = mem::zeroed();
+            hdr.msg_name = &mut storages[i] as *mut _ as *mut _;
+            hdr.msg_namelen = namelen;
+            hdr.msg_iov = &mut iovecs[i] as *mut _;
+            hdr.msg_iovlen = 1;
+            libc::mmsghdr { msg_hdr: hdr, msg_len: 0 }
+        }).collect();
+
+        let mut sent = 0;
+        while sent < headers.len() {
+            let result = unsafe {
+                libc::sendmmsg(
+                    self.socket.as_raw_fd(),
+                    headers[sent..].as_mut_ptr(),
+                    (headers.len() - sent) as libc::c_uint,
+                    0)
+            };
+            if result < 0 {
+                if sent > 0 {
+                    break;
+                }
+                return Err(::std::io::Error::last_os_error().into());
+            }
+            if result == 0 {
+                break;
+            }
+            sent += result as usize;
+        }
+
+        Ok(sent)
+    }
+
+    #[cfg(not(unix))]
+    fn send_batch_encoded(&mut self, encoded: &[(Vec<u8>, SocketAddr)]) -> UnrResult<usize> {
+        try!(self.socket.set_nonblocking(true));
+        let mut sent = 0;
+        for &(ref bytes, addr) in encoded {
+            loop {
+                match self.socket.send_to(&bytes[..], addr) {
+                    Ok(_) => { sent += 1; break; }
+                    Err(ref e) if e.kind() == IoErrorKind::WouldBlock => continue,
+                    Err(e) => {
+                        try!(self.socket.set_nonblocking(false));
+                        if sent > 0 {
+                            return Ok(sent);
+                        }
+                        return Err(e.into());
+                    }
+                }
+            }
+        }
+        try!(self.socket.set_nonblocking(false));
+        Ok(sent)
+    }
+}
+
+#[cfg(unix)]
+fn socket_addr_to_sockaddr_storage(addr: SocketAddr) -> libc::sockaddr_storage {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    match addr {
+        SocketAddr::V4(v4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: v4.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from(*v4.ip()).to_be() },
+                sin_zero: unsafe { mem::zeroed() },
+            };
+            unsafe { ::std::ptr::copy_nonoverlapping(&sin, &mut storage as *mut _ as *mut libc::sockaddr_in, 1) };
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: v6.port().to_be(),
+                sin6_flowinfo: v6.flowinfo(),
+                sin6_addr: libc::in6_addr { s6_addr: v6.ip().octets() },
+                sin6_scope_id: v6.scope_id(),
+            };
+            unsafe { ::std::ptr::copy_nonoverlapping(&sin6, &mut storage as *mut _ as *mut libc::sockaddr_in6, 1) };
+        }
+    }
+    storage
+}
+
+#[cfg(unix)]
+fn sockaddr_len_and_family(addr: &SocketAddr) -> (libc::socklen_t, libc::sa_family_t) {
+    match *addr {
+        SocketAddr::V4(_) => (mem::size_of::<libc::sockaddr_in>() as libc::socklen_t, libc::AF_INET as libc::sa_family_t),
+        SocketAddr::V6(_) => (mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t, libc::AF_INET6 as libc::sa_family_t),
+    }
+}
+
+/// Abandons every id an `OrderWindow` is waiting on, from `next_id` up to
+/// (but not including) the lowest id it already has buffered, jumping
+/// forward to that id and returning one `Skipped` event per id abandoned.
+fn skip_gap(window: &mut OrderWindow) -> Vec<OrderedEvent> {
+    let lowest = *window.buffered.keys().next().expect("gap only tracked while something is buffered");
+    let events = (window.next_id .. lowest).map(|id| OrderedEvent::Skipped(MsgId(id))).collect();
+    window.next_id = lowest;
+    window.gap_since = None;
+    events
+}
+
+/// Consumes an `OrderWindow` being evicted, producing one event for every
+/// id from `next_id` through the highest id it has buffered: a `Message`
+/// for ids it actually completed, a `Skipped` for ids still missing.
+/// Empty if nothing was buffered.
+fn drain_window(window: OrderWindow) -> Vec<OrderedEvent> {
+    let OrderWindow { next_id, mut buffered, .. } = window;
+    let last = match buffered.keys().next_back() {
+        Some(&last) => last,
+        None => return Vec::new()
+    };
+    (next_id .. last + 1).map(|id| {
+        match buffered.remove(&id) {
+            Some(msg) => OrderedEvent::Message(msg),
+            None => OrderedEvent::Skipped(MsgId(id))
+        }
+    }).collect()
+}
+
+/// Resolves the first address a `ToSocketAddrs` yields, for call sites that
+/// (like `send_to`) only ever act on one concrete destination.
+fn resolve_addr<T: ToSocketAddrs>(addrs: &T) -> UnrResult<SocketAddr> {
+    let addr = try!(addrs.to_socket_addrs()).next();
+    addr.ok_or(::std::io::Error::new(::std::io::ErrorKind::InvalidInput, "no addresses to send to"))
+        .map_err(|e| e.into())
+}
+
+/// The on-wire size a `Datagram` would take up, used to decide whether one
+/// more chunk can still be packed into the current datagram.
+fn datagram_encoded_len(datagram: &Datagram) -> UnrResult<usize> {
+    let bytes = try!(bincode::encode(datagram, bincode::SizeLimit::Infinite));
+    Ok(bytes.len())
 }